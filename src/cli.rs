@@ -0,0 +1,179 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result, anyhow};
+use bytesize::ByteSize;
+use clap::Parser;
+use serde::Deserialize;
+
+/// Fill an MTP storage device down to a chosen amount of free space.
+///
+/// Any flag left unset falls back to an interactive prompt, so the tool can
+/// be driven fully unattended (e.g. from cron or CI) once `--device`,
+/// `--storage`, `--leave-free` and `--yes` are all given.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Device to use: either its index as shown in the selection prompt, or
+    /// its `VID:PID` (e.g. `04e8:6860`). The Windows backend accepts the
+    /// index only and rejects a `VID:PID` selector.
+    #[arg(long)]
+    pub device: Option<String>,
+
+    /// Storage ID to use on the selected device (as shown in the selection prompt)
+    #[arg(long)]
+    pub storage: Option<String>,
+
+    /// How much space to leave free on the device, e.g. "10MiB"
+    #[arg(long)]
+    pub leave_free: Option<String>,
+
+    /// Skip the confirmation prompt before deleting the local filler file
+    #[arg(long)]
+    pub yes: bool,
+
+    /// Securely overwrite previously-deleted data on the device instead of
+    /// just leaving free space: fills the storage, pushes it, deletes it,
+    /// and repeats for `--passes` passes
+    #[arg(long)]
+    pub wipe: bool,
+
+    /// Number of fill/push/delete passes to run in `--wipe` mode
+    #[arg(long, default_value_t = 3)]
+    pub passes: usize,
+
+    /// After sending the filler file, read it back from the device and
+    /// confirm its size matches what was sent
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Strengthen `--verify` by also comparing a hash of the downloaded
+    /// bytes against a hash taken while the filler file was written
+    #[arg(long)]
+    pub verify_hash: bool,
+
+    /// Path to a config file with persistent defaults (default: ./mtp-filler.toml)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+}
+
+impl Cli {
+    /// The `--leave-free` flag parsed into bytes, if given.
+    pub fn leave_free_bytes(&self) -> Result<Option<ByteSize>> {
+        self.leave_free
+            .as_deref()
+            .map(ByteSize::from_str)
+            .transpose()
+            .map_err(|e| anyhow!(e))
+    }
+}
+
+/// Persistent defaults read from a config file, mirroring how other
+/// storage tools keep a default disk-usage setting around instead of
+/// requiring it on every invocation.
+#[derive(Deserialize, Default, Debug)]
+pub struct Config {
+    pub leave_free: Option<String>,
+}
+
+impl Config {
+    const DEFAULT_PATH: &'static str = "mtp-filler.toml";
+
+    /// Loads the config file at `path`, or `./mtp-filler.toml` if `path` is
+    /// `None`. Missing files are not an error; they just yield defaults.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let path = path.unwrap_or_else(|| Path::new(Self::DEFAULT_PATH));
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    /// The configured `leave_free` default, parsed into bytes, if given.
+    pub fn leave_free_bytes(&self) -> Result<Option<ByteSize>> {
+        self.leave_free
+            .as_deref()
+            .map(ByteSize::from_str)
+            .transpose()
+            .map_err(|e| anyhow!(e))
+    }
+}
+
+/// Parses the `--storage` flag into a numeric storage ID, for the backends
+/// (libmtp) that identify storages by number rather than by string.
+pub fn parse_storage_id(selector: Option<&str>) -> Result<Option<u32>> {
+    selector
+        .map(|s| s.parse::<u32>().context("Storage ID must be a number"))
+        .transpose()
+}
+
+/// A device selector as given on the command line: either a plain index
+/// into the detected-devices list, or a `VID:PID` pair.
+#[derive(Debug)]
+pub enum DeviceSelector {
+    Index(usize),
+    VidPid(u16, u16),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(selector: &str) -> Result<Self> {
+        if let Ok(index) = selector.parse::<usize>() {
+            return Ok(DeviceSelector::Index(index));
+        }
+        let (vid, pid) = selector
+            .split_once(':')
+            .context("Device selector must be an index or VID:PID (e.g. 04e8:6860)")?;
+        let vid = u16::from_str_radix(vid, 16).context("Invalid VID in device selector")?;
+        let pid = u16::from_str_radix(pid, 16).context("Invalid PID in device selector")?;
+        Ok(DeviceSelector::VidPid(vid, pid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_selector_parses_index() {
+        match DeviceSelector::from_str("2").unwrap() {
+            DeviceSelector::Index(2) => {}
+            other => panic!("expected Index(2), got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn device_selector_parses_vid_pid() {
+        match DeviceSelector::from_str("04e8:6860").unwrap() {
+            DeviceSelector::VidPid(0x04e8, 0x6860) => {}
+            other => panic!("expected VidPid(0x04e8, 0x6860), got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn device_selector_rejects_garbage() {
+        assert!(DeviceSelector::from_str("not-a-selector").is_err());
+    }
+
+    #[test]
+    fn config_load_missing_file_yields_default() {
+        let path = PathBuf::from("./mtp-filler_test_missing.toml");
+        assert!(!path.exists());
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.leave_free, None);
+    }
+
+    #[test]
+    fn config_load_parses_leave_free() {
+        let path = PathBuf::from("./mtp-filler_test_config.toml");
+        fs::write(&path, "leave_free = \"10MiB\"\n").unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.leave_free_bytes().unwrap(), Some(ByteSize::mib(10)));
+    }
+}
@@ -11,6 +11,7 @@ use anyhow::{Result, anyhow};
 use bytesize::ByteSize;
 use dialoguer::{Confirm, Input};
 use indicatif::{ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 pub fn make_progres_bar(size: u64, message: impl Into<Cow<'static, str>>) -> Result<ProgressBar> {
@@ -23,28 +24,103 @@ pub fn make_progres_bar(size: u64, message: impl Into<Cow<'static, str>>) -> Res
     Ok(bar)
 }
 
-pub fn create_filler_file(current_free_bytes: ByteSize) -> Result<PathBuf> {
-    const BUFFER_SIZE: usize = 1024;
-    let input_size = Input::new()
-        .with_prompt("How much space should be left on device?")
-        .validate_with(|input: &String| -> Result<(), String> {
-            let input_size = ByteSize::from_str(&input)?;
-            if input_size >= current_free_bytes {
-                Err(
+/// A byte pattern to write into the filler buffer, so `--wipe` can overwrite
+/// previously-deleted data with something other than random noise.
+#[derive(Clone, Copy, Debug)]
+pub enum FillPattern {
+    Zeros,
+    Ones,
+    Random,
+}
+
+impl FillPattern {
+    pub fn fill(&self, buffer: &mut [u8]) {
+        match self {
+            FillPattern::Zeros => buffer.fill(0x00),
+            FillPattern::Ones => buffer.fill(0xFF),
+            FillPattern::Random => fastrand::fill(buffer),
+        }
+    }
+}
+
+/// The pattern to use for a given (0-indexed) pass of a multi-pass wipe:
+/// zeros, then ones, alternating, with the final pass always random.
+pub fn wipe_pattern_for_pass(pass: usize, total_passes: usize) -> FillPattern {
+    if pass + 1 >= total_passes {
+        FillPattern::Random
+    } else if pass % 2 == 0 {
+        FillPattern::Zeros
+    } else {
+        FillPattern::Ones
+    }
+}
+
+/// The filler file written to local disk, plus the hash of its contents if
+/// one was requested (used by `--verify-hash` to confirm the transfer was
+/// not silently truncated or corrupted).
+pub struct FillerFile {
+    pub path: PathBuf,
+    pub hash: Option<Vec<u8>>,
+}
+
+/// The minimum amount of space a user/config may ask to leave free on a
+/// device; `--wipe` passes keep the same 1 KiB as their safety margin.
+const MIN_LEAVE_FREE: ByteSize = ByteSize::b(1024);
+
+/// Resolves how much space to leave free given `current_free_bytes`, honoring
+/// `leave_free` when given and otherwise falling back to an interactive
+/// prompt (per chunk0-1's "any flag left unset falls back to an interactive
+/// prompt"). Shared by the fill paths of both backends so the validation and
+/// its error messages live in exactly one place; `--wipe` passes do not use it.
+pub fn resolve_leave_free(
+    current_free_bytes: ByteSize,
+    leave_free: Option<ByteSize>,
+) -> Result<ByteSize> {
+    match leave_free {
+        Some(leave_free) => {
+            if leave_free >= current_free_bytes {
+                return Err(anyhow!(
                     "Desired free bytes cannot be larger than current free space on device"
-                        .to_string(),
-                )
-            } else if input_size < ByteSize::b(BUFFER_SIZE.try_into().unwrap()) {
-                Err("Desired free bytes must be larger than 1024 bytes (1 KiB)".to_string())
-            } else {
-                Ok(())
+                ));
+            } else if leave_free < MIN_LEAVE_FREE {
+                return Err(anyhow!(
+                    "Desired free bytes must be at least 1024 bytes (1 KiB)"
+                ));
             }
-        })
-        .default("10MiB".to_string())
-        .interact_text()?;
-    let input_bytes = ByteSize::from_str(&input_size).map_err(|e| anyhow!(e))?;
+            Ok(leave_free)
+        }
+        None => {
+            let input_size = Input::new()
+                .with_prompt("How much space should be left on device?")
+                .validate_with(|input: &String| -> Result<(), String> {
+                    let input_size = ByteSize::from_str(input)?;
+                    if input_size >= current_free_bytes {
+                        Err(
+                            "Desired free bytes cannot be larger than current free space on device"
+                                .to_string(),
+                        )
+                    } else if input_size < MIN_LEAVE_FREE {
+                        Err("Desired free bytes must be at least 1024 bytes (1 KiB)".to_string())
+                    } else {
+                        Ok(())
+                    }
+                })
+                .default("10MiB".to_string())
+                .interact_text()?;
+            ByteSize::from_str(&input_size).map_err(|e| anyhow!(e))
+        }
+    }
+}
+
+pub fn create_filler_file(
+    current_free_bytes: ByteSize,
+    leave_free: ByteSize,
+    pattern: FillPattern,
+    compute_hash: bool,
+) -> Result<FillerFile> {
+    const BUFFER_SIZE: usize = 1024;
 
-    let filler_file_size = current_free_bytes - input_bytes.as_u64();
+    let filler_file_size = current_free_bytes - leave_free.as_u64();
     let filler_file_size: usize = filler_file_size.as_u64().try_into()?;
 
     // put random uuid in file name to avoid overwriting an existing file with the same name
@@ -58,31 +134,104 @@ pub fn create_filler_file(current_free_bytes: ByteSize) -> Result<PathBuf> {
 
     let mut buffer = [0; BUFFER_SIZE];
     let mut remaining_size = filler_file_size;
+    let mut hasher = compute_hash.then(Sha256::new);
 
     while remaining_size > 0 {
         let to_write = cmp::min(remaining_size, buffer.len());
         let buffer = &mut buffer[..to_write];
-        fastrand::fill(buffer);
+        pattern.fill(buffer);
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buffer);
+        }
         writer.write(buffer).unwrap();
 
         remaining_size -= to_write;
         bar.inc(1024);
     }
     bar.finish_and_clear();
-    Ok(filler_path)
+    let hash = hasher.map(|hasher| hasher.finalize().to_vec());
+    Ok(FillerFile {
+        path: filler_path,
+        hash,
+    })
 }
 
-pub fn delete_fillter_file(path: impl AsRef<Path>) -> Result<()> {
-    let prompt = format!(
-        "Delete the local filler file? ({})",
-        path.as_ref().display()
-    );
-    let input = Confirm::new()
-        .with_prompt(prompt)
-        .default(true)
-        .interact()?;
-    if input {
+pub fn delete_fillter_file(path: impl AsRef<Path>, skip_confirm: bool) -> Result<()> {
+    let confirmed = if skip_confirm {
+        true
+    } else {
+        let prompt = format!(
+            "Delete the local filler file? ({})",
+            path.as_ref().display()
+        );
+        Confirm::new()
+            .with_prompt(prompt)
+            .default(true)
+            .interact()?
+    };
+    if confirmed {
         remove_file(path)?;
     }
     Ok(())
 }
+
+/// Removes the local staged filler file when dropped unless [`Self::disarm`]
+/// was called first, so a wipe pass that fails after its filler file has been
+/// staged does not leave that file behind on local disk. A failure while the
+/// file is still being written is not covered.
+pub struct FillerCleanupGuard {
+    path: PathBuf,
+    armed: bool,
+}
+
+impl FillerCleanupGuard {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, armed: true }
+    }
+
+    pub fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for FillerCleanupGuard {
+    fn drop(&mut self) {
+        if self.armed {
+            let _ = remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wipe_pattern_alternates_then_ends_random() {
+        assert!(matches!(wipe_pattern_for_pass(0, 3), FillPattern::Zeros));
+        assert!(matches!(wipe_pattern_for_pass(1, 3), FillPattern::Ones));
+        assert!(matches!(wipe_pattern_for_pass(2, 3), FillPattern::Random));
+    }
+
+    #[test]
+    fn wipe_pattern_single_pass_is_random() {
+        assert!(matches!(wipe_pattern_for_pass(0, 1), FillPattern::Random));
+    }
+
+    #[test]
+    fn resolve_leave_free_accepts_valid_value() {
+        let resolved = resolve_leave_free(ByteSize::mib(100), Some(ByteSize::mib(10))).unwrap();
+        assert_eq!(resolved, ByteSize::mib(10));
+    }
+
+    #[test]
+    fn resolve_leave_free_rejects_value_at_or_above_free_space() {
+        assert!(resolve_leave_free(ByteSize::mib(100), Some(ByteSize::mib(100))).is_err());
+        assert!(resolve_leave_free(ByteSize::mib(100), Some(ByteSize::mib(200))).is_err());
+    }
+
+    #[test]
+    fn resolve_leave_free_rejects_value_below_minimum() {
+        assert!(resolve_leave_free(ByteSize::mib(100), Some(ByteSize::b(100))).is_err());
+    }
+}
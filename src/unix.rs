@@ -1,41 +1,69 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
 use bytesize::ByteSize;
 use chrono::{DateTime, Utc};
-use dialoguer::Select;
+use dialoguer::{MultiSelect, Select};
+use indicatif::{MultiProgress, ProgressBar};
 use libmtp_rs::device::raw::detect_raw_devices;
 use libmtp_rs::device::{MtpDevice, StorageSort};
 use libmtp_rs::object::filetypes::Filetype;
 use libmtp_rs::storage::Parent;
 use libmtp_rs::storage::files::FileMetadata;
 use libmtp_rs::util::CallbackReturn;
-use std::fs::metadata;
-use std::io::Write;
-use std::path::Path;
+use nix::sys::statvfs::statvfs;
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::fs::{File, metadata, remove_file};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use uuid::Uuid;
 
-use crate::shared::{create_filler_file, delete_fillter_file, make_progres_bar};
+use crate::cli::{Cli, Config, DeviceSelector, parse_storage_id};
+use crate::shared::{
+    FillPattern, FillerCleanupGuard, create_filler_file, delete_fillter_file, make_progres_bar,
+    resolve_leave_free, wipe_pattern_for_pass,
+};
 
-fn select_device() -> Result<MtpDevice> {
+fn select_device(selector: Option<&str>) -> Result<MtpDevice> {
     let raw_devices = detect_raw_devices()?;
-    let raw_devices_string = raw_devices
-        .iter()
-        .enumerate()
-        .map(|(i, dev)| {
-            let entry = dev.device_entry();
-            format!(
-                "ID {}: {} {} (VID: {}, PID: {})",
-                i, entry.vendor, entry.product, entry.product_id, entry.vendor_id
-            )
-        })
-        .collect::<Vec<_>>();
 
-    let input = Select::new()
-        .with_prompt("Select the device to use")
-        .default(0)
-        .items(raw_devices_string)
-        .interact()?;
+    let index = match selector {
+        Some(selector) => match selector.parse::<DeviceSelector>()? {
+            DeviceSelector::Index(index) => index,
+            DeviceSelector::VidPid(vid, pid) => raw_devices
+                .iter()
+                .position(|dev| {
+                    let entry = dev.device_entry();
+                    entry.vendor_id == vid && entry.product_id == pid
+                })
+                .with_context(|| format!("No device found with VID:PID {:04x}:{:04x}", vid, pid))?,
+        },
+        None => {
+            let raw_devices_string = raw_devices
+                .iter()
+                .enumerate()
+                .map(|(i, dev)| {
+                    let entry = dev.device_entry();
+                    format!(
+                        "ID {}: {} {} (VID: {}, PID: {})",
+                        i, entry.vendor, entry.product, entry.vendor_id, entry.product_id
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            Select::new()
+                .with_prompt("Select the device to use")
+                .default(0)
+                .items(raw_devices_string)
+                .interact()?
+        }
+    };
 
     let selected_device = raw_devices
-        .get(input)
+        .get(index)
         .context("Failed to select device")?
         .open_uncached()
         .context("Failed to open device")?;
@@ -43,8 +71,16 @@ fn select_device() -> Result<MtpDevice> {
     Ok(selected_device)
 }
 
-fn select_storage(device: &MtpDevice) -> Result<u32> {
+fn select_storage(device: &MtpDevice, storage_id: Option<u32>) -> Result<u32> {
     let storage_pools = device.storage_pool();
+
+    if let Some(storage_id) = storage_id {
+        storage_pools
+            .by_id(storage_id)
+            .with_context(|| format!("No storage found with ID {}", storage_id))?;
+        return Ok(storage_id);
+    }
+
     let storage_pool_vec = storage_pools.iter().collect::<Vec<_>>();
     let storage_pool_strings = storage_pools
         .iter()
@@ -79,8 +115,8 @@ fn send_file_to_device(
     storage_id: u32,
     filler_file_path: impl AsRef<Path>,
     metadata: FileMetadata,
+    bar: &ProgressBar,
 ) -> Result<()> {
-    let bar = make_progres_bar(1, "Sending file to device")?;
     let pool = device.storage_pool();
     let storage = pool.by_id(storage_id).context("Could not select storage")?;
 
@@ -98,6 +134,303 @@ fn send_file_to_device(
     Ok(())
 }
 
+/// Confirms the local working directory has at least `required` bytes free,
+/// so staging a filler file (as `--wipe` does) fails fast with a clear message
+/// instead of partway through the write loop.
+fn ensure_local_free_space(required: u64) -> Result<()> {
+    let stats = statvfs(".").context("Failed to check local free space")?;
+    let available = stats.blocks_available() as u64 * stats.fragment_size() as u64;
+    if available < required {
+        return Err(anyhow!(
+            "Not enough local free space to stage the filler file ({} available, {} required)",
+            ByteSize::b(available).display(),
+            ByteSize::b(required).display()
+        ));
+    }
+    Ok(())
+}
+
+/// Generates `size` bytes of filler on the fly and streams them straight
+/// into the MTP send, without ever staging them on local disk. Returns the
+/// hash of the generated bytes if one was requested.
+fn stream_filler_to_device(
+    device: &MtpDevice,
+    storage_id: u32,
+    size: u64,
+    pattern: FillPattern,
+    compute_hash: bool,
+    file_name: &str,
+    bar: &ProgressBar,
+) -> Result<Option<Vec<u8>>> {
+    let (mut tx, rx) = UnixStream::pair().context("Failed to create filler pipe")?;
+
+    let generator = thread::spawn(move || -> Result<Option<Vec<u8>>> {
+        const BUFFER_SIZE: usize = 1024;
+        let mut buffer = [0u8; BUFFER_SIZE];
+        let mut hasher = compute_hash.then(Sha256::new);
+        let mut remaining = size;
+        while remaining > 0 {
+            let to_write = cmp::min(remaining, BUFFER_SIZE as u64) as usize;
+            let chunk = &mut buffer[..to_write];
+            pattern.fill(chunk);
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&chunk);
+            }
+            tx.write_all(chunk)?;
+            remaining -= to_write as u64;
+        }
+        drop(tx);
+        Ok(hasher.map(|hasher| hasher.finalize().to_vec()))
+    });
+
+    let pool = device.storage_pool();
+    let storage = pool.by_id(storage_id).context("Could not select storage")?;
+    let metadata = FileMetadata {
+        file_name,
+        file_size: size,
+        file_type: Filetype::Unknown,
+        modification_date: Utc::now(),
+    };
+
+    storage.send_file_from_fd_with_callback(
+        rx.as_raw_fd(),
+        Parent::Root,
+        metadata,
+        |sent, total| {
+            bar.set_length(total);
+            bar.set_position(sent);
+            std::io::stdout().lock().flush().expect("Failed to flush");
+            CallbackReturn::Continue
+        },
+    )?;
+
+    generator
+        .join()
+        .map_err(|_| anyhow!("Filler generator thread panicked"))?
+}
+
+/// A selected device together with the IDs of its storages chosen as fill
+/// targets; the storages of one device are filled one after another.
+struct DeviceGroup {
+    device: Arc<MtpDevice>,
+    storage_ids: Vec<u32>,
+}
+
+/// A single storage queued for filling, with its filler file name and size
+/// decided up front so it can be streamed straight to the device.
+struct PreparedTarget {
+    group_index: usize,
+    storage_id: u32,
+    label: String,
+    file_name: String,
+    filler_file_size: u64,
+}
+
+fn default_selection(len: usize) -> Vec<bool> {
+    let mut defaults = vec![false; len];
+    if let Some(first) = defaults.first_mut() {
+        *first = true;
+    }
+    defaults
+}
+
+fn select_devices(selector: Option<&str>) -> Result<Vec<Arc<MtpDevice>>> {
+    let raw_devices = detect_raw_devices()?;
+
+    let indices = match selector {
+        Some(selector) => vec![match selector.parse::<DeviceSelector>()? {
+            DeviceSelector::Index(index) => index,
+            DeviceSelector::VidPid(vid, pid) => raw_devices
+                .iter()
+                .position(|dev| {
+                    let entry = dev.device_entry();
+                    entry.vendor_id == vid && entry.product_id == pid
+                })
+                .with_context(|| format!("No device found with VID:PID {:04x}:{:04x}", vid, pid))?,
+        }],
+        None => {
+            let raw_devices_string = raw_devices
+                .iter()
+                .enumerate()
+                .map(|(i, dev)| {
+                    let entry = dev.device_entry();
+                    format!(
+                        "ID {}: {} {} (VID: {}, PID: {})",
+                        i, entry.vendor, entry.product, entry.vendor_id, entry.product_id
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            let selected = MultiSelect::new()
+                .with_prompt("Select one or more devices to use")
+                .items(&raw_devices_string)
+                .defaults(&default_selection(raw_devices_string.len()))
+                .interact()?;
+            if selected.is_empty() {
+                return Err(anyhow!("No device selected"));
+            }
+            selected
+        }
+    };
+
+    indices
+        .into_iter()
+        .map(|index| {
+            raw_devices
+                .get(index)
+                .context("Failed to select device")?
+                .open_uncached()
+                .context("Failed to open device")
+                .map(Arc::new)
+        })
+        .collect()
+}
+
+fn select_storages(device: &MtpDevice, storage_id: Option<u32>) -> Result<Vec<u32>> {
+    let storage_pools = device.storage_pool();
+
+    if let Some(storage_id) = storage_id {
+        storage_pools
+            .by_id(storage_id)
+            .with_context(|| format!("No storage found with ID {}", storage_id))?;
+        return Ok(vec![storage_id]);
+    }
+
+    let storage_pool_vec = storage_pools.iter().collect::<Vec<_>>();
+    let storage_pool_strings = storage_pool_vec
+        .iter()
+        .map(|(_, storage)| {
+            format!(
+                "ID {}: {} (capacity: {}, free space: {})",
+                storage.id(),
+                storage.description().unwrap_or("-"),
+                ByteSize::b(storage.maximum_capacity()).display(),
+                ByteSize::b(storage.free_space_in_bytes()).display()
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let selected = MultiSelect::new()
+        .with_prompt("Select one or more storages to use")
+        .items(&storage_pool_strings)
+        .defaults(&default_selection(storage_pool_strings.len()))
+        .interact()?;
+    if selected.is_empty() {
+        return Err(anyhow!("No storage selected"));
+    }
+    Ok(selected
+        .into_iter()
+        .map(|i| storage_pool_vec[i].1.id())
+        .collect())
+}
+
+fn select_device_groups(cli: &Cli) -> Result<Vec<DeviceGroup>> {
+    let devices = select_devices(cli.device.as_deref())?;
+    let storage_override = parse_storage_id(cli.storage.as_deref())?;
+
+    devices
+        .into_iter()
+        .map(|device| {
+            let storage_ids = select_storages(&device, storage_override)?;
+            Ok(DeviceGroup { device, storage_ids })
+        })
+        .collect()
+}
+
+fn delete_filler_object(device: &mut MtpDevice, storage_id: u32, file_name: &str) -> Result<()> {
+    // The storage pool is a local cache that only reflects the object we
+    // just pushed once refreshed, so this must run before the lookup below.
+    device.update_storage(StorageSort::NotSorted)?;
+    let pool = device.storage_pool();
+    let storage = pool.by_id(storage_id).context("Could not select storage")?;
+    let (object_id, _) = storage
+        .files()
+        .iter()
+        .find(|(_, file)| file.name() == file_name)
+        .context("Could not find pushed filler file on device")?;
+    device.delete_object(object_id)?;
+    Ok(())
+}
+
+/// Reads the just-pushed object back from the device and confirms its
+/// reported size matches what was sent; with `expected_hash`, also
+/// re-downloads its bytes and compares their hash, to catch corruption a
+/// size check alone would miss.
+fn verify_uploaded_object(
+    device: &mut MtpDevice,
+    storage_id: u32,
+    file_name: &str,
+    expected_size: u64,
+    expected_hash: Option<&[u8]>,
+) -> Result<()> {
+    // Same cache-refresh requirement as delete_filler_object above.
+    device.update_storage(StorageSort::NotSorted)?;
+    let pool = device.storage_pool();
+    let storage = pool.by_id(storage_id).context("Could not select storage")?;
+    let (object_id, file) = storage
+        .files()
+        .iter()
+        .find(|(_, file)| file.name() == file_name)
+        .context("Could not find pushed filler file on device to verify")?;
+
+    if file.file_size() != expected_size {
+        return Err(anyhow!(
+            "Verification failed: device reports size {} but {} bytes were sent",
+            file.file_size(),
+            expected_size
+        ));
+    }
+
+    if let Some(expected_hash) = expected_hash {
+        let download_path = PathBuf::from(format!("./{}_verify.txt", Uuid::new_v4()));
+        storage.get_file_to_path(object_id, &download_path)?;
+        let mut hasher = Sha256::new();
+        let mut f = File::open(&download_path)?;
+        io::copy(&mut f, &mut hasher)?;
+        remove_file(&download_path)?;
+        if hasher.finalize().as_slice() != expected_hash {
+            return Err(anyhow!(
+                "Verification failed: hash of downloaded file does not match the data that was sent"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn wipe_free_space(device: &mut MtpDevice, storage_id: u32, passes: usize) -> Result<()> {
+    let safety_margin = ByteSize::b(1024);
+
+    for pass in 0..passes {
+        println!("Wipe pass {}/{}", pass + 1, passes);
+        let pattern = wipe_pattern_for_pass(pass, passes);
+        let free_space = get_free_space(device, storage_id)?;
+        let filler_size = free_space
+            .as_u64()
+            .checked_sub(safety_margin.as_u64())
+            .context("Device free space is too small to leave a safety margin")?;
+        ensure_local_free_space(filler_size)?;
+        let filler = create_filler_file(free_space, safety_margin, pattern, false)?;
+        let filler_file_path = filler.path.canonicalize()?;
+        let cleanup_guard = FillerCleanupGuard::new(filler_file_path.clone());
+        let meta = get_metadata(&filler_file_path)?;
+        let file_name = meta.file_name.to_string();
+        let bar = make_progres_bar(1, "Sending file to device")?;
+        if let Err(err) = send_file_to_device(device, storage_id, &filler_file_path, meta, &bar) {
+            // best-effort: a partial write may not have created an object at all
+            let _ = delete_filler_object(device, storage_id, &file_name);
+            return Err(err);
+        }
+        // Device-side cleanup must happen unconditionally once the push
+        // succeeded, regardless of whether the local delete below succeeds.
+        delete_filler_object(device, storage_id, &file_name)?;
+        delete_fillter_file(&filler_file_path, true)?;
+        cleanup_guard.disarm();
+        device.update_storage(StorageSort::NotSorted)?;
+    }
+    Ok(())
+}
+
 fn get_metadata(path: &Path) -> Result<FileMetadata> {
     let meta = metadata(path)?;
     let modification_date: DateTime<Utc> = meta.modified()?.into();
@@ -114,20 +447,135 @@ fn get_metadata(path: &Path) -> Result<FileMetadata> {
     })
 }
 
-pub fn run() -> Result<()> {
-    let mut device = select_device()?;
-    let storage_id = select_storage(&device)?;
-    let free_space = get_free_space(&device, storage_id)?;
-    let filler_file_path = create_filler_file(free_space)?;
-    let filler_file_path = filler_file_path.canonicalize()?;
-    let meta = get_metadata(&filler_file_path)?;
-    send_file_to_device(&device, storage_id, &filler_file_path, meta)?;
-    delete_fillter_file(&filler_file_path)?;
-    device.update_storage(StorageSort::NotSorted)?;
-    let remaining_free_space = get_free_space(&device, storage_id)?;
-    println!(
-        "Successfully filled MTP storage, remaining free space is: {}",
-        remaining_free_space.display()
-    );
+pub fn run(cli: &Cli) -> Result<()> {
+    let config = Config::load(cli.config.as_deref())?;
+    let leave_free = cli.leave_free_bytes()?.or(config.leave_free_bytes()?);
+
+    if cli.wipe {
+        let mut device = select_device(cli.device.as_deref())?;
+        let storage_id = select_storage(&device, parse_storage_id(cli.storage.as_deref())?)?;
+        wipe_free_space(&mut device, storage_id, cli.passes)?;
+        let remaining_free_space = get_free_space(&device, storage_id)?;
+        println!(
+            "Successfully wiped free space on MTP storage, remaining free space is: {}",
+            remaining_free_space.display()
+        );
+        return Ok(());
+    }
+
+    let mut groups = select_device_groups(cli)?;
+    let compute_hash = cli.verify_hash;
+
+    let mut prepared = Vec::new();
+    for (group_index, group) in groups.iter().enumerate() {
+        for &storage_id in &group.storage_ids {
+            let free_space = get_free_space(&group.device, storage_id)?;
+            let leave_free_for_target = resolve_leave_free(free_space, leave_free)?;
+            let filler_file_size = free_space.as_u64() - leave_free_for_target.as_u64();
+            let file_name = format!("{}_filler.txt", Uuid::new_v4());
+
+            let pool = group.device.storage_pool();
+            let storage = pool.by_id(storage_id).context("Could not select storage")?;
+            let label = format!(
+                "Device {} / Storage {}: {}",
+                group_index,
+                storage_id,
+                storage.description().unwrap_or("-")
+            );
+
+            prepared.push(PreparedTarget {
+                group_index,
+                storage_id,
+                label,
+                file_name,
+                filler_file_size,
+            });
+        }
+    }
+
+    let multi_progress = MultiProgress::new();
+    let bars = prepared
+        .iter()
+        .map(|target| -> Result<ProgressBar> {
+            Ok(multi_progress.add(make_progres_bar(1, target.label.clone())?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Group targets by device: MTP/PTP is a single-session protocol, so two
+    // storages on the same physical device can't be streamed to
+    // concurrently without risking corrupting the transfer. Storages on the
+    // same device are therefore filled sequentially by one thread per
+    // device, while distinct devices still fill in parallel.
+    let mut indices_by_group: Vec<Vec<usize>> = vec![Vec::new(); groups.len()];
+    for (index, target) in prepared.iter().enumerate() {
+        indices_by_group[target.group_index].push(index);
+    }
+
+    let hashes = thread::scope(|scope| -> Result<Vec<Option<Vec<u8>>>> {
+        let handles = indices_by_group
+            .into_iter()
+            .filter(|indices| !indices.is_empty())
+            .map(|indices| {
+                let device = Arc::clone(&groups[prepared[indices[0]].group_index].device);
+                scope.spawn(move || -> Result<Vec<(usize, Option<Vec<u8>>)>> {
+                    indices
+                        .into_iter()
+                        .map(|index| {
+                            let target = &prepared[index];
+                            let hash = stream_filler_to_device(
+                                &device,
+                                target.storage_id,
+                                target.filler_file_size,
+                                FillPattern::Random,
+                                compute_hash,
+                                &target.file_name,
+                                &bars[index],
+                            )?;
+                            Ok((index, hash))
+                        })
+                        .collect()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut hashes: Vec<Option<Vec<u8>>> = vec![None; prepared.len()];
+        for handle in handles {
+            let results = handle
+                .join()
+                .map_err(|_| anyhow!("Upload thread panicked"))??;
+            for (index, hash) in results {
+                hashes[index] = hash;
+            }
+        }
+        Ok(hashes)
+    })?;
+
+    if cli.verify || cli.verify_hash {
+        for (target, hash) in prepared.iter().zip(hashes.iter()) {
+            verify_uploaded_object(
+                Arc::get_mut(&mut groups[target.group_index].device)
+                    .context("Device still in use by another thread")?,
+                target.storage_id,
+                &target.file_name,
+                target.filler_file_size,
+                hash.as_deref(),
+            )?;
+            println!("Verified {}", target.label);
+        }
+    }
+
+    for group in groups.iter_mut() {
+        Arc::get_mut(&mut group.device)
+            .context("Device still in use by another thread")?
+            .update_storage(StorageSort::NotSorted)?;
+    }
+
+    println!("Successfully filled MTP storage, remaining free space per target:");
+    for target in &prepared {
+        let group = &groups[target.group_index];
+        let remaining_free_space = get_free_space(&group.device, target.storage_id)?;
+        println!("  {}: {}", target.label, remaining_free_space.display());
+    }
+
     Ok(())
 }
@@ -1,10 +1,17 @@
-use std::path::Path;
+use std::fs::{File, metadata, remove_file};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use anyhow::{Context, Result, anyhow};
 use bytesize::ByteSize;
-use dialoguer::Select;
+use dialoguer::{MultiSelect, Select};
 use dunce::canonicalize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
 use widestring::U16CString;
+use windows::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+use windows::core::PCWSTR;
 use winmtp::{
     PROPERTYKEY,
     PortableDevices::{WPD_STORAGE_CAPACITY, WPD_STORAGE_FREE_SPACE_IN_BYTES},
@@ -12,7 +19,11 @@ use winmtp::{
     device::Device,
 };
 
-use crate::shared::{create_filler_file, delete_fillter_file};
+use crate::cli::{Cli, Config, DeviceSelector};
+use crate::shared::{
+    FillPattern, FillerCleanupGuard, create_filler_file, delete_fillter_file, make_progres_bar,
+    resolve_leave_free, wipe_pattern_for_pass,
+};
 
 fn get_bytes_from_property(
     device: &Device,
@@ -30,34 +41,64 @@ fn get_bytes_from_property(
     Ok(bytes)
 }
 
-fn select_device() -> Result<Device> {
+/// Selects a single device, intentionally unlike unix.rs's multi-device
+/// `select_devices`: a WPD `Device`/`Provider` session is bound to the COM
+/// apartment of the thread that created it, so fanning fills out across
+/// physical devices the way the libmtp backend does would require each
+/// worker thread to initialize and own its own COM apartment. That's real,
+/// separate work and is out of scope here -- the Windows backend is
+/// single-device, with its storages filled sequentially (see `run`).
+fn select_device(selector: Option<&str>) -> Result<Device> {
     let app_ident = winmtp::make_current_app_identifiers!();
     let provider = Provider::new()?;
     let raw_devices = provider.enumerate_devices()?;
     if raw_devices.len() < 1 {
         return Err(anyhow!("No attached MTP devices detected"));
     }
-    let raw_devices_string = raw_devices
-        .iter()
-        .enumerate()
-        .map(|(i, dev)| format!("ID {}: {}", i, dev.friendly_name()))
-        .collect::<Vec<_>>();
-    let input = Select::new()
-        .with_prompt("Select the device to use")
-        .default(0)
-        .items(raw_devices_string)
-        .interact()?;
+
+    let index = match selector {
+        Some(selector) => match selector.parse::<DeviceSelector>()? {
+            DeviceSelector::Index(index) => index,
+            DeviceSelector::VidPid(..) => {
+                return Err(anyhow!(
+                    "Selecting a device by VID:PID is not supported on the Windows backend, use its index instead"
+                ));
+            }
+        },
+        None => {
+            let raw_devices_string = raw_devices
+                .iter()
+                .enumerate()
+                .map(|(i, dev)| format!("ID {}: {}", i, dev.friendly_name()))
+                .collect::<Vec<_>>();
+            Select::new()
+                .with_prompt("Select the device to use")
+                .default(0)
+                .items(raw_devices_string)
+                .interact()?
+        }
+    };
+
     let selected_device = raw_devices
-        .get(input)
+        .get(index)
         .context("Failed to select device")?
         .open(&app_ident, true)?;
     Ok(selected_device)
 }
 
-fn select_storage(device: &Device) -> Result<U16CString> {
+fn select_storage(device: &Device, storage_id: Option<&str>) -> Result<U16CString> {
     let content = device.content()?;
     let root = content.root()?;
     let children = root.children()?.into_iter().collect::<Vec<_>>();
+
+    if let Some(storage_id) = storage_id {
+        return children
+            .iter()
+            .find(|v| v.id().to_string_lossy() == storage_id)
+            .map(|v| v.id().into())
+            .with_context(|| format!("No storage found with ID {}", storage_id));
+    }
+
     let children_string = children
         .iter()
         .map(|v| {
@@ -79,6 +120,51 @@ fn select_storage(device: &Device) -> Result<U16CString> {
     Ok(child.id().into())
 }
 
+fn select_storages(device: &Device, storage_id: Option<&str>) -> Result<Vec<U16CString>> {
+    let content = device.content()?;
+    let root = content.root()?;
+    let children = root.children()?.into_iter().collect::<Vec<_>>();
+
+    if let Some(storage_id) = storage_id {
+        return children
+            .iter()
+            .find(|v| v.id().to_string_lossy() == storage_id)
+            .map(|v| vec![v.id().into()])
+            .with_context(|| format!("No storage found with ID {}", storage_id));
+    }
+
+    let children_string = children
+        .iter()
+        .map(|v| {
+            format!(
+                "ID {}: {} (capacity: {} free space: {})",
+                v.id().to_string_lossy(),
+                v.name().to_string_lossy(),
+                get_capacity(device, v.id().into()).unwrap(),
+                get_free_space(device, v.id().into()).unwrap()
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut defaults = vec![false; children_string.len()];
+    if let Some(first) = defaults.first_mut() {
+        *first = true;
+    }
+
+    let selected = MultiSelect::new()
+        .with_prompt("Select one or more storages to use")
+        .items(&children_string)
+        .defaults(&defaults)
+        .interact()?;
+    if selected.is_empty() {
+        return Err(anyhow!("No storage selected"));
+    }
+    Ok(selected
+        .into_iter()
+        .map(|i| children[i].id().into())
+        .collect())
+}
+
 fn get_capacity(device: &Device, storage_id: U16CString) -> Result<ByteSize> {
     get_bytes_from_property(&device, storage_id, WPD_STORAGE_CAPACITY)
 }
@@ -92,28 +178,218 @@ fn send_file_to_device(
     storage_id: U16CString,
     file_path: impl AsRef<Path>,
 ) -> Result<()> {
+    const BLOCK_SIZE: usize = 1024 * 1024;
+
+    let file_path = file_path.as_ref();
+    let file_name = file_path
+        .file_name()
+        .context("Filler path terminates in ..")?
+        .to_string_lossy()
+        .to_string();
+    let file_size = metadata(file_path)?.len();
+
     let content = device.content()?;
     let storage = content.object_by_id(storage_id)?;
+    let mut stream = storage.open_write_stream(&file_name, file_size)?;
 
-    println!("\nSending file to device, this may take a while because MTP is slow,");
-    println!("for example, 1GB may take up to 2 minutes");
-    println!("There will be no progress indicator, please be patient...");
-    storage.push_file(file_path.as_ref(), false)?;
+    let mut reader = File::open(file_path)?;
+    let bar = make_progres_bar(file_size, "Sending file to device")?;
+    let mut buffer = vec![0u8; BLOCK_SIZE];
+    let mut sent = 0u64;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        stream.write_all(&buffer[..read])?;
+        sent += read as u64;
+        bar.set_position(sent);
+    }
+    stream.flush()?;
+    bar.finish_and_clear();
+    Ok(())
+}
+
+/// Confirms the local working directory has at least `required` bytes free,
+/// so staging the filler file fails fast with a clear message
+/// instead of partway through the write loop.
+fn ensure_local_free_space(required: u64) -> Result<()> {
+    let path = U16CString::from_str(".")?;
+    let mut free_bytes_available = 0u64;
+    unsafe {
+        GetDiskFreeSpaceExW(
+            PCWSTR(path.as_ptr()),
+            Some(&mut free_bytes_available),
+            None,
+            None,
+        )
+        .context("Failed to check local free space")?;
+    }
+    if free_bytes_available < required {
+        return Err(anyhow!(
+            "Not enough local free space to stage the filler file ({} available, {} required)",
+            ByteSize::b(free_bytes_available).display(),
+            ByteSize::b(required).display()
+        ));
+    }
     Ok(())
 }
 
-pub fn run() -> Result<()> {
-    let device = select_device()?;
-    let storage_id = select_storage(&device)?;
-    let free_space = get_free_space(&device, storage_id.clone())?;
-    let filler_file_path = create_filler_file(free_space)?;
-    let filler_file_path = canonicalize(filler_file_path)?;
-    send_file_to_device(&device, storage_id.clone(), &filler_file_path)?;
-    delete_fillter_file(&filler_file_path)?;
-    let remaining_free_space = get_free_space(&device, storage_id.clone())?;
-    println!(
-        "Successfully filled mtp storage, remaining free space is: {}",
-        remaining_free_space.display()
-    );
+fn delete_filler_object(device: &Device, storage_id: U16CString, file_name: &str) -> Result<()> {
+    let content = device.content()?;
+    let storage = content.object_by_id(storage_id)?;
+    let object = storage
+        .children()?
+        .into_iter()
+        .find(|child| child.name().to_string_lossy() == file_name)
+        .context("Could not find pushed filler file on device")?;
+    object.delete()?;
+    Ok(())
+}
+
+/// Reads the just-pushed object back from the device and confirms its
+/// reported size matches what was sent; with `expected_hash`, also
+/// re-downloads its bytes and compares their hash, to catch corruption a
+/// size check alone would miss.
+fn verify_uploaded_object(
+    device: &Device,
+    storage_id: U16CString,
+    file_name: &str,
+    expected_size: u64,
+    expected_hash: Option<&[u8]>,
+) -> Result<()> {
+    let content = device.content()?;
+    let storage = content.object_by_id(storage_id)?;
+    let object = storage
+        .children()?
+        .into_iter()
+        .find(|child| child.name().to_string_lossy() == file_name)
+        .context("Could not find pushed filler file on device to verify")?;
+
+    let actual_size = object.size()?;
+    if actual_size != expected_size {
+        return Err(anyhow!(
+            "Verification failed: device reports size {} but {} bytes were sent",
+            actual_size,
+            expected_size
+        ));
+    }
+
+    if let Some(expected_hash) = expected_hash {
+        let download_path = PathBuf::from(format!("./{}_verify.txt", Uuid::new_v4()));
+        object.pull_to_path(&download_path)?;
+        let mut hasher = Sha256::new();
+        let mut f = File::open(&download_path)?;
+        io::copy(&mut f, &mut hasher)?;
+        remove_file(&download_path)?;
+        if hasher.finalize().as_slice() != expected_hash {
+            return Err(anyhow!(
+                "Verification failed: hash of downloaded file does not match the data that was sent"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn wipe_free_space(device: &Device, storage_id: U16CString, passes: usize) -> Result<()> {
+    let safety_margin = ByteSize::b(1024);
+
+    for pass in 0..passes {
+        println!("Wipe pass {}/{}", pass + 1, passes);
+        let pattern = wipe_pattern_for_pass(pass, passes);
+        let free_space = get_free_space(device, storage_id.clone())?;
+        let filler_size = free_space
+            .as_u64()
+            .checked_sub(safety_margin.as_u64())
+            .context("Device free space is too small to leave a safety margin")?;
+        ensure_local_free_space(filler_size)?;
+        let filler = create_filler_file(free_space, safety_margin, pattern, false)?;
+        let filler_file_path = canonicalize(filler.path)?;
+        let cleanup_guard = FillerCleanupGuard::new(filler_file_path.clone());
+        let file_name = filler_file_path
+            .file_name()
+            .context("Filler path terminates in ..")?
+            .to_string_lossy()
+            .to_string();
+        if let Err(err) = send_file_to_device(device, storage_id.clone(), &filler_file_path) {
+            // best-effort: a partial write may not have created an object at all
+            let _ = delete_filler_object(device, storage_id.clone(), &file_name);
+            return Err(err);
+        }
+        // Device-side cleanup must happen unconditionally once the push
+        // succeeded, regardless of whether the local delete below succeeds.
+        delete_filler_object(device, storage_id.clone(), &file_name)?;
+        delete_fillter_file(&filler_file_path, true)?;
+        cleanup_guard.disarm();
+    }
+    Ok(())
+}
+
+pub fn run(cli: &Cli) -> Result<()> {
+    let config = Config::load(cli.config.as_deref())?;
+    let leave_free = cli.leave_free_bytes()?.or(config.leave_free_bytes()?);
+
+    let device = select_device(cli.device.as_deref())?;
+
+    if cli.wipe {
+        let storage_id = select_storage(&device, cli.storage.as_deref())?;
+        wipe_free_space(&device, storage_id.clone(), cli.passes)?;
+        let remaining_free_space = get_free_space(&device, storage_id)?;
+        println!(
+            "Successfully wiped free space on mtp storage, remaining free space is: {}",
+            remaining_free_space.display()
+        );
+        return Ok(());
+    }
+
+    // The Windows Portable Devices interface is a single COM session per
+    // device, same constraint as libmtp on unix, so storages are filled one
+    // at a time in sequence rather than concurrently. Unlike unix.rs, this
+    // also doesn't fan out across multiple physical devices in parallel --
+    // see the note on select_device for why that's scoped out here rather
+    // than silently dropped.
+    let storage_ids = select_storages(&device, cli.storage.as_deref())?;
+    for storage_id in storage_ids {
+        let label = format!("Storage {}", storage_id.to_string_lossy());
+        println!("Filling {}", label);
+
+        let free_space = get_free_space(&device, storage_id.clone())?;
+        let leave_free_for_target = resolve_leave_free(free_space, leave_free)?;
+        ensure_local_free_space(free_space.as_u64() - leave_free_for_target.as_u64())?;
+        let filler = create_filler_file(
+            free_space,
+            leave_free_for_target,
+            FillPattern::Random,
+            cli.verify_hash,
+        )?;
+        let filler_file_path = canonicalize(filler.path)?;
+        let filler_file_size = metadata(&filler_file_path)?.len();
+        send_file_to_device(&device, storage_id.clone(), &filler_file_path)?;
+
+        if cli.verify || cli.verify_hash {
+            let file_name = filler_file_path
+                .file_name()
+                .context("Filler path terminates in ..")?
+                .to_string_lossy()
+                .to_string();
+            verify_uploaded_object(
+                &device,
+                storage_id.clone(),
+                &file_name,
+                filler_file_size,
+                filler.hash.as_deref(),
+            )?;
+            println!("Verified {}", label);
+        }
+
+        delete_fillter_file(&filler_file_path, cli.yes)?;
+        let remaining_free_space = get_free_space(&device, storage_id.clone())?;
+        println!(
+            "Successfully filled {}, remaining free space is: {}",
+            label,
+            remaining_free_space.display()
+        );
+    }
     Ok(())
 }